@@ -1,10 +1,12 @@
 use std::{
-    ffi::{OsStr, OsString},
+    ffi::OsStr,
     fmt::Display,
-    fs::{self, File},
+    fs,
     hash::Hash,
     io::{self, BufRead, BufReader, Read},
     path::{Path, PathBuf},
+    process,
+    str::FromStr,
 };
 
 use bumpalo::Bump;
@@ -22,6 +24,34 @@ struct Opts {
     /// file containing keys to ignore
     #[clap(short, long)]
     ignore: Option<String>,
+    /// only report keys whose value changed; omit additions and removals
+    #[clap(long)]
+    changed_only: bool,
+    /// match files by bare filename instead of path relative to the tree root
+    #[clap(long)]
+    by_name: bool,
+    /// output format: `text` or `json`
+    #[clap(long, default_value = "text")]
+    format: Format,
+}
+
+/// Output format for the difference report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown format `{}` (expected `text` or `json`)", other)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -36,10 +66,18 @@ impl Display for Key<'_> {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DifferenceKind {
+    Changed,
+    OnlyLeft,
+    OnlyRight,
+}
+
 struct Difference<'a> {
     key: Key<'a>,
-    left: String,
-    right: String,
+    kind: DifferenceKind,
+    left: Option<String>,
+    right: Option<String>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -73,49 +111,184 @@ fn main() -> io::Result<()> {
         ignored.initialize(&fs::read_to_string(path)?);
     }
 
-    let tree = read_common_tree(&opts.left, &opts.right);
+    let tree = read_common_tree(&opts.left, &opts.right, opts.by_name);
     let store = Bump::new();
 
+    let mut reporter: Box<dyn Reporter> = match opts.format {
+        Format::Text => Box::new(TextReporter),
+        Format::Json => Box::new(JsonReporter),
+    };
+    let mut found_differences = false;
+    let mut stdout = io::stdout();
+
     for (file, (left, right)) in tree {
         let differences: Vec<_> = diff_paths(&left, &right, &store)?
             .filter(|x| !ignored.is_ignored(&x.key))
+            .filter(|x| !opts.changed_only || x.kind == DifferenceKind::Changed)
             .collect();
 
         if !differences.is_empty() {
-            println!("# {} ({})", file.to_string_lossy(), differences.len());
-            for difference in differences {
-                println!(
+            found_differences = true;
+            reporter.report_file(&mut stdout, &file, &differences)?;
+        }
+    }
+
+    if found_differences {
+        process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Renders the differences found for a single file. Only called when
+/// `differences` is non-empty. Writes through `out` rather than stdout
+/// directly so tests can capture output into a buffer.
+trait Reporter {
+    fn report_file(
+        &mut self,
+        out: &mut dyn io::Write,
+        path: &Path,
+        differences: &[Difference],
+    ) -> io::Result<()>;
+}
+
+struct TextReporter;
+
+impl Reporter for TextReporter {
+    fn report_file(
+        &mut self,
+        out: &mut dyn io::Write,
+        path: &Path,
+        differences: &[Difference],
+    ) -> io::Result<()> {
+        writeln!(out, "# {} ({})", path.to_string_lossy(), differences.len())?;
+        for difference in differences {
+            match difference.kind {
+                DifferenceKind::Changed => writeln!(
+                    out,
                     "  {}\n    {}\n    {}",
-                    difference.key, difference.left, difference.right
-                );
+                    difference.key,
+                    difference.left.as_deref().unwrap_or_default(),
+                    difference.right.as_deref().unwrap_or_default(),
+                )?,
+                DifferenceKind::OnlyLeft => writeln!(
+                    out,
+                    "  - {}\n    {}",
+                    difference.key,
+                    difference.left.as_deref().unwrap_or_default(),
+                )?,
+                DifferenceKind::OnlyRight => writeln!(
+                    out,
+                    "  + {}\n    {}",
+                    difference.key,
+                    difference.right.as_deref().unwrap_or_default(),
+                )?,
             }
         }
+        Ok(())
     }
+}
 
-    Ok(())
+struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn report_file(
+        &mut self,
+        out: &mut dyn io::Write,
+        path: &Path,
+        differences: &[Difference],
+    ) -> io::Result<()> {
+        let records: Vec<_> = differences.iter().map(difference_to_json).collect();
+        writeln!(
+            out,
+            r#"{{"file":{},"differences":[{}]}}"#,
+            json_string(&path.to_string_lossy()),
+            records.join(",")
+        )
+    }
+}
+
+fn difference_to_json(difference: &Difference) -> String {
+    let kind = match difference.kind {
+        DifferenceKind::Changed => "changed",
+        DifferenceKind::OnlyLeft => "removed",
+        DifferenceKind::OnlyRight => "added",
+    };
+
+    format!(
+        r#"{{"key":{},"left":{},"right":{},"kind":"{}"}}"#,
+        json_string(&difference.key.to_string()),
+        json_opt_string(difference.left.as_deref()),
+        json_opt_string(difference.right.as_deref()),
+        kind,
+    )
+}
+
+fn json_opt_string(value: Option<&str>) -> String {
+    match value {
+        Some(value) => json_string(value),
+        None => "null".to_string(),
+    }
+}
+
+/// Renders `value` as a quoted, escaped JSON string.
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for ch in value.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
 }
 
+/// Pairs up files present in both trees, keyed on the path relative to each
+/// tree's root (so e.g. `SimObjects/Airplanes/A/panel.cfg` is only compared
+/// against the same relative path on the other side). Pass `by_name` to fall
+/// back to the old bare-filename keying for intentionally reorganized trees.
 fn read_common_tree(
     left: &str,
     right: &str,
-) -> impl Iterator<Item = (OsString, (PathBuf, PathBuf))> {
+    by_name: bool,
+) -> impl Iterator<Item = (PathBuf, (PathBuf, PathBuf))> {
+    let key = move |relative: PathBuf, absolute: &Path| -> PathBuf {
+        if by_name {
+            PathBuf::from(absolute.file_name().unwrap())
+        } else {
+            relative
+        }
+    };
+
     let left: HashMap<_, _> = read_tree(left)
-        .map(|x| (x.file_name().unwrap().to_owned(), x))
+        .map(|(relative, absolute)| (key(relative, &absolute), absolute))
         .collect();
     let mut right: HashMap<_, _> = read_tree(right)
-        .map(|x| (x.file_name().unwrap().to_owned(), x))
+        .map(|(relative, absolute)| (key(relative, &absolute), absolute))
         .collect();
 
     left.into_iter()
         .filter_map(move |(file, left)| right.remove(&file).map(|right| (file, (left, right))))
 }
 
-fn read_tree(root: &str) -> impl Iterator<Item = PathBuf> {
+/// Walks `root` for `.cfg`/`.CFG` files, yielding each as `(relative, absolute)`
+/// where `relative` is the path with `root` stripped off the front.
+fn read_tree(root: &str) -> impl Iterator<Item = (PathBuf, PathBuf)> {
     let tgt_ext = OsStr::new("cfg");
     let tgt_ext_cap = OsStr::new("CFG");
+    let root = PathBuf::from(root);
 
-    WalkDir::new(root).into_iter().filter_map(move |entry| {
-        entry
+    WalkDir::new(root.clone()).into_iter().filter_map(move |entry| {
+        let absolute = entry
             .ok()
             .filter(|x| {
                 x.path()
@@ -123,81 +296,388 @@ fn read_tree(root: &str) -> impl Iterator<Item = PathBuf> {
                     .map(|ext| ext == tgt_ext || ext == tgt_ext_cap)
                     .unwrap_or_default()
             })
-            .map(|x| x.into_path())
+            .map(|x| x.into_path())?;
+
+        let relative = absolute
+            .strip_prefix(&root)
+            .unwrap_or(&absolute)
+            .to_path_buf();
+        Some((relative, absolute))
     })
 }
 
-fn diff_paths(
+fn diff_paths<'a>(
     left: impl AsRef<Path>,
     right: impl AsRef<Path>,
-    store: &Bump,
-) -> io::Result<impl Iterator<Item = Difference>> {
-    let left = File::open(left)?;
-    let right = File::open(right)?;
-    Ok(diff(left, right, store))
+    store: &'a Bump,
+) -> io::Result<impl Iterator<Item = Difference<'a>>> {
+    let left = read_to_map_from_path(left, store)?;
+    let right = read_to_map_from_path(right, store)?;
+    Ok(diff(left, right))
 }
 
-fn diff(left: impl Read, right: impl Read, store: &Bump) -> impl Iterator<Item = Difference> + '_ {
-    let left = read_to_map(left, store);
-    let mut right = read_to_map(right, store);
-
-    left.into_iter().filter_map(move |(key, value)| {
-        let other = right.remove(&key)?;
-        if value != other {
-            Some(Difference {
+fn diff<'a>(
+    left: HashMap<Key<'a>, String>,
+    mut right: HashMap<Key<'a>, String>,
+) -> impl Iterator<Item = Difference<'a>> {
+    let mut differences: Vec<_> = left
+        .into_iter()
+        .filter_map(|(key, value)| match right.remove(&key) {
+            Some(other) if other != value => Some(Difference {
                 key,
-                left: value,
-                right: other,
-            })
-        } else {
-            None
+                kind: DifferenceKind::Changed,
+                left: Some(value),
+                right: Some(other),
+            }),
+            Some(_) => None,
+            None => Some(Difference {
+                key,
+                kind: DifferenceKind::OnlyLeft,
+                left: Some(value),
+                right: None,
+            }),
+        })
+        .collect();
+
+    differences.extend(right.into_iter().map(|(key, value)| Difference {
+        key,
+        kind: DifferenceKind::OnlyRight,
+        left: None,
+        right: Some(value),
+    }));
+
+    differences.into_iter()
+}
+
+/// Abstracts over where a `.cfg` file's bytes come from, so include
+/// resolution can run against the real filesystem in production or an
+/// in-memory fixture tree in tests.
+trait ConfigSource {
+    fn read(&self, path: &Path) -> io::Result<String>;
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+struct FsSource;
+
+impl ConfigSource for FsSource {
+    fn read(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+/// Reads a `.cfg` file into a `Key`/value map, resolving any `%include`
+/// directives along the way.
+fn read_to_map_from_path(path: impl AsRef<Path>, store: &Bump) -> io::Result<HashMap<Key, String>> {
+    let mut visited = Vec::new();
+    let text = resolve_includes(&FsSource, path.as_ref(), &mut visited)?;
+    Ok(read_to_map(text.as_bytes(), store))
+}
+
+/// Expands `%include <path>` directives in `path`, returning the merged
+/// source text. `visited` is the chain of files currently being expanded
+/// (pushed on entry, popped on return), so a cycle is stopped without also
+/// forbidding a harmless diamond include from two non-cyclic places. Since
+/// the merged text is parsed top to bottom as a single stream, a `[section]`
+/// header inside an include would otherwise leak past it, so we track the
+/// most recent section seen in *this* file and re-emit it after the include.
+fn resolve_includes(
+    source: &impl ConfigSource,
+    path: &Path,
+    visited: &mut Vec<PathBuf>,
+) -> io::Result<String> {
+    let canonical = source.canonicalize(path)?;
+    if visited.contains(&canonical) {
+        return Ok(String::new());
+    }
+    visited.push(canonical);
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let contents = source.read(path)?;
+    let mut resolved = String::with_capacity(contents.len());
+    let mut section = "[root]";
+
+    for line in contents.lines() {
+        let stripped = strip_unquoted_comment(line).trim();
+        if stripped.starts_with('[') && stripped.ends_with(']') {
+            section = stripped;
         }
-    })
+
+        match stripped.strip_prefix("%include") {
+            Some(include) => {
+                let include_path = dir.join(include.trim());
+                resolved.push_str(&resolve_includes(source, &include_path, visited)?);
+                resolved.push_str(section);
+                resolved.push('\n');
+            }
+            None => resolved.push_str(line),
+        }
+        resolved.push('\n');
+    }
+
+    visited.pop();
+    Ok(resolved)
 }
 
+/// Tokenizes `config` into a `Key`/value map, modeled loosely on a
+/// Mercurial-style config reader: `[section]` headers, `key = value`
+/// assignments, `%unset key` deletions, indented continuation lines that
+/// extend the previous value, and `#`/`;` comments are all recognized.
 fn read_to_map(config: impl Read, store: &Bump) -> HashMap<Key, String> {
     let mut section = store.alloc_str("root");
-    let mut map = HashMap::new();
+    let mut map: HashMap<Key, String> = HashMap::new();
+    let mut last_key: Option<Key> = None;
 
     let config = BufReader::new(config);
-    let config = config
-        .lines()
-        .filter_map(Result::ok)
-        .filter(|x| !x.is_empty() && !is_whitespace(&x));
-
-    for line in config {
-        let line = match line.find(';') {
-            Some(idx) => {
-                let (line, _comment) = line.split_at(idx);
-                line.trim()
+
+    for line in config.lines().filter_map(Result::ok) {
+        if line.is_empty() || is_whitespace(&line) {
+            continue;
+        }
+
+        let trimmed = line.trim_start();
+        if trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with(char::is_whitespace) {
+            if let Some(value) = last_key.as_ref().and_then(|key| map.get_mut(key)) {
+                value.push('\n');
+                value.push_str(trimmed.trim_end());
             }
-            None => line.trim(),
-        };
+            continue;
+        }
 
+        let line = strip_unquoted_comment(&line).trim();
         if line.is_empty() {
             continue;
         }
 
         if line.starts_with('[') && line.ends_with(']') {
             section = store.alloc_str(&line[1..(line.len() - 1)]);
+            last_key = None;
+            continue;
+        }
+
+        if let Some(property) = line.strip_prefix("%unset") {
+            map.remove(&Key {
+                section,
+                property: property.trim().to_string(),
+            });
+            last_key = None;
             continue;
         }
 
         if let Some(idx) = line.find('=') {
             let (key, value) = line.split_at(idx);
-            map.insert(
-                Key {
-                    section,
-                    property: key.trim().to_string(),
-                },
-                value.trim().to_string(),
-            );
+            let key = Key {
+                section,
+                property: key.trim().to_string(),
+            };
+
+            map.insert(key.clone(), value[1..].trim().to_string());
+            last_key = Some(key);
         }
     }
 
     map
 }
 
+/// Strips a trailing `;`/`#` comment from a value, ignoring any such
+/// characters that fall inside a double-quoted span so a value like
+/// `"a;b" ; real comment` keeps its quoted portion intact.
+fn strip_unquoted_comment(s: &str) -> &str {
+    let mut in_quotes = false;
+
+    for (idx, ch) in s.char_indices() {
+        match ch {
+            '"' => in_quotes = !in_quotes,
+            ';' | '#' if !in_quotes => return &s[..idx],
+            _ => {}
+        }
+    }
+
+    s
+}
+
 fn is_whitespace(s: &str) -> bool {
     s.chars().all(|x| x.is_whitespace())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// An in-memory [`ConfigSource`] backed by a fixture's virtual files,
+    /// keyed by the path given after each `//- <path>` marker line.
+    struct MapSource {
+        files: HashMap<PathBuf, String>,
+    }
+
+    impl ConfigSource for MapSource {
+        fn read(&self, path: &Path) -> io::Result<String> {
+            self.files.get(path).cloned().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no such fixture file: {}", path.display()),
+                )
+            })
+        }
+
+        fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+            Ok(path.to_path_buf())
+        }
+    }
+
+    /// A set of virtual files parsed out of a single fixture string, in the
+    /// style of rust-analyzer's inline multi-file fixtures: each file starts
+    /// with a `//- /relative/path.cfg` marker line, and every line up to the
+    /// next marker (or end of input) is its content.
+    struct Fixture {
+        files: HashMap<PathBuf, String>,
+    }
+
+    impl Fixture {
+        fn parse(text: &str) -> Self {
+            let mut files = HashMap::new();
+            let mut current: Option<(PathBuf, String)> = None;
+
+            for line in text.lines() {
+                match line.strip_prefix("//- ") {
+                    Some(path) => {
+                        if let Some((path, content)) = current.take() {
+                            files.insert(path, content);
+                        }
+                        current = Some((PathBuf::from(path.trim()), String::new()));
+                    }
+                    None => {
+                        if let Some((_, content)) = current.as_mut() {
+                            content.push_str(line);
+                            content.push('\n');
+                        }
+                    }
+                }
+            }
+
+            if let Some((path, content)) = current.take() {
+                files.insert(path, content);
+            }
+
+            Fixture { files }
+        }
+
+        fn source(&self) -> MapSource {
+            MapSource {
+                files: self.files.clone(),
+            }
+        }
+    }
+
+    /// Builds a `Key`/value map for every file in `fixture`, resolving
+    /// `%include` directives against the fixture's own virtual files rather
+    /// than the real filesystem.
+    fn read_fixture_tree<'a>(
+        fixture: &Fixture,
+        store: &'a Bump,
+    ) -> HashMap<PathBuf, HashMap<Key<'a>, String>> {
+        let source = fixture.source();
+        fixture
+            .files
+            .keys()
+            .map(|path| {
+                let mut visited = Vec::new();
+                let text = resolve_includes(&source, path, &mut visited).unwrap();
+                (path.clone(), read_to_map(text.as_bytes(), store))
+            })
+            .collect()
+    }
+
+    /// Renders a diff report for a left/right fixture pair through a real
+    /// [`Reporter`], so golden files read like actual CLI output and the
+    /// tokenizer, `diff`, and the reporter's own formatting/escaping are all
+    /// exercised by the same test. Differences are sorted by key so the
+    /// report is stable regardless of hash map iteration order.
+    fn render_fixture_diff(left: &str, right: &str, reporter: &mut dyn Reporter) -> String {
+        let store = Bump::new();
+        let left_fixture = Fixture::parse(left);
+        let right_fixture = Fixture::parse(right);
+
+        let mut left_tree = read_fixture_tree(&left_fixture, &store);
+        let right_tree = read_fixture_tree(&right_fixture, &store);
+
+        let mut paths: Vec<_> = left_tree.keys().cloned().collect();
+        paths.sort();
+
+        let mut out = Vec::new();
+        for path in paths {
+            let left_map = left_tree.remove(&path).unwrap();
+            let right_map = match right_tree.get(&path) {
+                Some(map) => map.clone(),
+                None => continue,
+            };
+
+            let mut differences: Vec<_> = diff(left_map, right_map).collect();
+            if differences.is_empty() {
+                continue;
+            }
+            differences.sort_by_key(|x| x.key.to_string());
+
+            reporter.report_file(&mut out, &path, &differences).unwrap();
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Runs every `<name>.left.fixture`/`<name>.right.fixture` pair under
+    /// `test_data/` against its `<name>.txt` (text) and `<name>.json.txt`
+    /// (JSON) golden files, `dir_tests`-style. Set `UPDATE_EXPECT=1` to
+    /// rewrite the golden files instead of failing.
+    #[test]
+    fn golden_fixtures() {
+        let update = env::var_os("UPDATE_EXPECT").is_some();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data");
+
+        for entry in fs::read_dir(&dir).expect("test_data directory") {
+            let path = entry.unwrap().path();
+            let name = match path.file_name().and_then(OsStr::to_str) {
+                Some(name) => name,
+                None => continue,
+            };
+            let stem = match name.strip_suffix(".left.fixture") {
+                Some(stem) => stem,
+                None => continue,
+            };
+
+            let left = fs::read_to_string(&path).unwrap();
+            let right = fs::read_to_string(dir.join(format!("{stem}.right.fixture"))).unwrap();
+
+            let actual_text = render_fixture_diff(&left, &right, &mut TextReporter);
+            let actual_json = render_fixture_diff(&left, &right, &mut JsonReporter);
+
+            let text_path = dir.join(format!("{stem}.txt"));
+            let json_path = dir.join(format!("{stem}.json.txt"));
+            if update {
+                fs::write(&text_path, &actual_text).unwrap();
+                fs::write(&json_path, &actual_json).unwrap();
+                continue;
+            }
+
+            let expected_text = fs::read_to_string(&text_path).unwrap_or_default();
+            assert_eq!(
+                actual_text, expected_text,
+                "text golden mismatch for {stem}; rerun with UPDATE_EXPECT=1 to refresh"
+            );
+
+            let expected_json = fs::read_to_string(&json_path).unwrap_or_default();
+            assert_eq!(
+                actual_json, expected_json,
+                "json golden mismatch for {stem}; rerun with UPDATE_EXPECT=1 to refresh"
+            );
+        }
+    }
+}